@@ -40,6 +40,10 @@ struct Context<'a, 'b> {
     local_numbers: &'a UniqueMap<Var, usize>,
     borrow_state: &'b mut BorrowState,
     diags: Diagnostics,
+    // Set while evaluating a `ModuleCall`/`Builtin`'s arguments, so that a mutable borrow
+    // created there is reserved (two-phase) rather than immediately active; see
+    // `BorrowState::borrow_local`/`borrow_field_path`.
+    reserving: bool,
 }
 
 impl<'a, 'b> Context<'a, 'b> {
@@ -49,6 +53,7 @@ impl<'a, 'b> Context<'a, 'b> {
             local_numbers,
             borrow_state,
             diags: Diagnostics::new(),
+            reserving: false,
         }
     }
 
@@ -61,6 +66,16 @@ impl<'a, 'b> Context<'a, 'b> {
     }
 }
 
+/// Evaluates `args`, a call's argument-list expression, with any mutable borrows it creates
+/// entered into the borrow graph as reserved rather than immediately active.
+fn call_arguments(context: &mut Context, args: &Exp) -> Values {
+    let was_reserving = context.reserving;
+    context.reserving = true;
+    let values = exp(context, args);
+    context.reserving = was_reserving;
+    values
+}
+
 impl TransferFunctions for BorrowSafety {
     type State = BorrowState;
 
@@ -84,6 +99,7 @@ impl AbstractInterpreter for BorrowSafety {}
 
 pub fn verify(
     compilation_env: &mut CompilationEnv,
+    fname: &str,
     signature: &FunctionSignature,
     acquires: &BTreeMap<StructName, Loc>,
     locals: &UniqueMap<Var, SingleType>,
@@ -96,6 +112,12 @@ pub fn verify(
     let mut safety = BorrowSafety::new(locals);
     initial_state.canonicalize_locals(&safety.local_numbers);
     let (final_state, ds) = safety.analyze_function(cfg, initial_state);
+    if compilation_env.flags().debug_borrow_graph() {
+        for (lbl, state) in &final_state {
+            let graph_name = format!("{}_block_{}", fname, lbl);
+            eprintln!("{}", state.borrow_graph_dot(&graph_name, &safety.local_numbers));
+        }
+    }
     compilation_env.add_diags(ds);
     final_state
 }
@@ -167,6 +189,23 @@ fn lvalue(context: &mut Context, sp!(loc, l_): &LValue, value: Value) {
     }
 }
 
+/// Walks down through a chain of nested field-projecting borrows that share `mut_`, collecting
+/// the fields they project through in source (root-to-leaf) order, and returns the underlying
+/// expression the chain bottoms out at. This lets a single source-level borrow like `&mut
+/// s.a.x` turn into one `borrow_field_path` call (and so one conflict check) instead of one
+/// call per `.`, which would otherwise report the same conflict once per intermediate field.
+fn borrow_chain<'e>(mut_: bool, e: &'e Exp) -> (&'e Exp, Vec<&'e Field>) {
+    use UnannotatedExp_ as E;
+    match &e.exp.value {
+        E::Borrow(inner_mut, inner_e, f) if *inner_mut == mut_ => {
+            let (base, mut fields) = borrow_chain(mut_, inner_e);
+            fields.push(f);
+            (base, fields)
+        }
+        _ => (e, vec![]),
+    }
+}
+
 fn exp(context: &mut Context, parent_e: &Exp) -> Values {
     use UnannotatedExp_ as E;
     let eloc = &parent_e.exp.loc;
@@ -184,7 +223,9 @@ fn exp(context: &mut Context, parent_e: &Exp) -> Values {
             vec![value]
         }
         E::BorrowLocal(mut_, var) => {
-            let (diags, value) = context.borrow_state.borrow_local(*eloc, *mut_, var);
+            let (diags, value) = context
+                .borrow_state
+                .borrow_local(*eloc, *mut_, var, context.reserving);
             context.add_diags(diags);
             assert!(value.is_ref());
             vec![value]
@@ -202,14 +243,22 @@ fn exp(context: &mut Context, parent_e: &Exp) -> Values {
             vec![value]
         }
         E::Borrow(mut_, e, f) => {
-            let evalue = assert_single_value(exp(context, e));
-            let (diags, value) = context.borrow_state.borrow_field(*eloc, *mut_, evalue, f);
+            let (base_e, mut fields) = borrow_chain(*mut_, e);
+            fields.push(f);
+            let evalue = assert_single_value(exp(context, base_e));
+            let (diags, value) = context.borrow_state.borrow_field_path(
+                *eloc,
+                *mut_,
+                evalue,
+                &fields,
+                context.reserving,
+            );
             context.add_diags(diags);
             vec![value]
         }
 
         E::Builtin(b, e) => {
-            let evalues = exp(context, e);
+            let evalues = call_arguments(context, e);
             let b: &BuiltinFunction = b;
             match b {
                 sp!(_, BuiltinFunction_::BorrowGlobal(mut_, t)) => {
@@ -245,7 +294,7 @@ fn exp(context: &mut Context, parent_e: &Exp) -> Values {
         }
 
         E::ModuleCall(mcall) => {
-            let evalues = exp(context, &mcall.arguments);
+            let evalues = call_arguments(context, &mcall.arguments);
             let ret_ty = &parent_e.ty;
             let (diags, values) =
                 context
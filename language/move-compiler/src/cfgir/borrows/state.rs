@@ -0,0 +1,614 @@
+// Copyright (c) The Diem Core Contributors
+// Copyright (c) The Move Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{collections::BTreeMap, fmt};
+
+use move_ir_types::location::*;
+
+use crate::{
+    diag,
+    diagnostics::{codes::BorrowSafety, Diagnostics},
+    hlir::ast::*,
+    parser::ast::{StructName, Var},
+    shared::unique_map::UniqueMap,
+};
+
+use super::super::absint::{AbstractDomain, JoinResult};
+
+//**************************************************************************************************
+// Values
+//**************************************************************************************************
+
+/// Identifies a single outstanding reference tracked by the borrow graph.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct RefID(usize);
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Value {
+    NonRef,
+    Ref(RefID),
+}
+pub type Values = Vec<Value>;
+
+impl Value {
+    pub fn is_ref(&self) -> bool {
+        matches!(self, Value::Ref(_))
+    }
+}
+
+//**************************************************************************************************
+// Loan paths
+//**************************************************************************************************
+
+/// The place a live reference was ultimately borrowed from: either a local in the current
+/// function, or a global resource accessed through `borrow_global`/`move_from`.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum LoanRoot {
+    Local(Var),
+    Global(StructName),
+    // A reference that did not come from any local or global we are tracking (e.g. one
+    // returned from a call): it is its own root, and so can never alias another loan.
+    Unaliased(RefID),
+}
+
+impl fmt::Display for LoanRoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoanRoot::Local(v) => write!(f, "{}", v),
+            LoanRoot::Global(s) => write!(f, "{}", s),
+            LoanRoot::Unaliased(_) => write!(f, "_"),
+        }
+    }
+}
+
+/// The root a loan was ultimately taken from, followed by the chain of field projections used
+/// to reach the sub-value that the reference actually points at. Two loans can only possibly
+/// alias when they share a root; `is_prefix_of`/`overlaps` compare the projection chains to
+/// answer exactly how they relate.
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct LoanPath {
+    root: LoanRoot,
+    fields: Vec<Field>,
+}
+
+impl LoanPath {
+    fn local(v: Var) -> Self {
+        Self {
+            root: LoanRoot::Local(v),
+            fields: vec![],
+        }
+    }
+
+    fn global(s: StructName) -> Self {
+        Self {
+            root: LoanRoot::Global(s),
+            fields: vec![],
+        }
+    }
+
+    fn extended(&self, f: &Field) -> Self {
+        let mut fields = self.fields.clone();
+        fields.push(f.clone());
+        Self {
+            root: self.root.clone(),
+            fields,
+        }
+    }
+
+    fn same_root(&self, other: &LoanPath) -> bool {
+        self.root == other.root
+    }
+
+    /// Is `self` a (non-strict) prefix of `other`, i.e. is `other` the same place as `self`, or
+    /// a sub-place reached by projecting further into it?
+    fn is_prefix_of(&self, other: &LoanPath) -> bool {
+        self.same_root(other) && other.fields.starts_with(&self.fields)
+    }
+
+    /// Do `self` and `other` name overlapping places? This holds when one path is a prefix of
+    /// the other (identical places, or one nested inside the other); distinct fields of a
+    /// shared base (`x.a` vs `x.b`) do not overlap.
+    fn overlaps(&self, other: &LoanPath) -> bool {
+        self.is_prefix_of(other) || other.is_prefix_of(self)
+    }
+
+}
+
+impl fmt::Display for LoanPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.root)?;
+        for field in &self.fields {
+            write!(f, ".{}", field)?;
+        }
+        Ok(())
+    }
+}
+
+//**************************************************************************************************
+// Borrow state
+//**************************************************************************************************
+
+/// A local's state, including the `Value` it's bound to while available: for a reference-typed
+/// local this is the `RefID` whose loan backs it, so that borrowing/moving/copying the local
+/// later sees (and can release) the same loan it was assigned, instead of forgetting it.
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum LocalState {
+    Unavailable,
+    Available(Value),
+}
+
+#[derive(Clone, Debug)]
+struct Loan {
+    mut_: bool,
+    path: LoanPath,
+    loc: Loc,
+    // A two-phase mutable borrow taken to build a call's argument list: while reserved, it only
+    // conflicts with other mutable borrows/mutations, not with shared reads, and is upgraded to
+    // a full exclusive borrow once `call` actually consumes it.
+    reserved: bool,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct BorrowState {
+    locals: UniqueMap<Var, LocalState>,
+    acquired_resources: BTreeMap<StructName, Loc>,
+    next_id: usize,
+    loans: BTreeMap<RefID, Loan>,
+    // Tracks which loan a field-projection borrow was taken from, so that reborrowing through a
+    // reference you already hold is never mistaken for a new, conflicting loan.
+    parents: BTreeMap<RefID, RefID>,
+    has_errors: bool,
+}
+
+impl PartialEq for Loan {
+    fn eq(&self, other: &Self) -> bool {
+        self.mut_ == other.mut_ && self.path == other.path
+    }
+}
+impl Eq for Loan {}
+
+impl BorrowState {
+    pub fn initial(
+        locals: &UniqueMap<Var, SingleType>,
+        acquires: BTreeMap<StructName, Loc>,
+        has_errors: bool,
+    ) -> Self {
+        let mut local_states = UniqueMap::new();
+        for (v, _) in locals.key_cloned_iter() {
+            local_states.add(v, LocalState::Unavailable).unwrap();
+        }
+        Self {
+            locals: local_states,
+            acquired_resources: acquires,
+            next_id: 0,
+            loans: BTreeMap::new(),
+            parents: BTreeMap::new(),
+            has_errors,
+        }
+    }
+
+    /// Binds each parameter to its initial value. A reference-typed parameter is backed by a
+    /// fresh loan rooted at the parameter itself (mirroring `fresh_unaliased_loan`'s handling of
+    /// ref-typed call returns), so that borrowing a field off of it goes through the ordinary
+    /// `borrow_field_path` conflict checks instead of finding nothing and panicking.
+    pub fn bind_arguments(&mut self, parameters: &[(Var, SingleType)]) {
+        for (v, ty) in parameters {
+            let value = match &ty.value {
+                SingleType_::Base(_) => Value::NonRef,
+                SingleType_::Ref(mut_, _) => {
+                    let path = LoanPath::local(*v);
+                    let id = self.fresh_id();
+                    self.loans.insert(
+                        id,
+                        Loan {
+                            mut_: *mut_,
+                            path,
+                            loc: ty.loc,
+                            reserved: false,
+                        },
+                    );
+                    Value::Ref(id)
+                }
+            };
+            self.locals.remove(v);
+            self.locals.add(*v, LocalState::Available(value)).unwrap();
+        }
+    }
+
+    pub fn canonicalize_locals(&mut self, local_numbers: &UniqueMap<Var, usize>) {
+        let mut canon = UniqueMap::new();
+        for (v, _) in local_numbers.key_cloned_iter() {
+            let state = self.locals.get(&v).cloned().unwrap_or(LocalState::Unavailable);
+            canon.add(v, state).unwrap();
+        }
+        self.locals = canon;
+    }
+
+    fn fresh_id(&mut self) -> RefID {
+        let id = RefID(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    fn ancestors(&self, id: RefID) -> Vec<RefID> {
+        let mut chain = vec![id];
+        let mut cur = id;
+        while let Some(parent) = self.parents.get(&cur) {
+            chain.push(*parent);
+            cur = *parent;
+        }
+        chain
+    }
+
+    /// All outstanding loans that would conflict with a new borrow of `path` with mutability
+    /// `mut_`, excluding any loan in `exclude` (the chain of loans this borrow was reborrowed
+    /// from, which never conflicts with itself).
+    ///
+    /// Two loans of the same base are only a conflict when either is mutable *and* their paths
+    /// overlap: `&mut s.f1` and `&mut s.f2` name disjoint fields and do not alias, but `&mut
+    /// s.a.x` and `&mut s.a` do, since the latter is a whole-value borrow of a base the former
+    /// projects into.
+    ///
+    /// A *reserved* two-phase borrow (see `Loan::reserved`) is exempt from this on the read
+    /// side: it conflicts with another mutable borrow or a mutation of an overlapping path (the
+    /// new operation passes `mut_ = true`), but not with an ordinary shared read, so `&v` can
+    /// still be evaluated while a reservation for `&mut v` is outstanding.
+    fn conflicting_loans(&self, mut_: bool, path: &LoanPath, exclude: &[RefID]) -> Vec<Loan> {
+        self.loans
+            .iter()
+            .filter(|(id, loan)| {
+                !exclude.contains(id)
+                    && (mut_ || (loan.mut_ && !loan.reserved))
+                    && loan.path.overlaps(path)
+            })
+            .map(|(_, loan)| loan.clone())
+            .collect()
+    }
+
+    fn borrow_conflict_diags(&self, loc: Loc, path: &LoanPath, conflicts: Vec<Loan>) -> Diagnostics {
+        let mut diags = Diagnostics::new();
+        for old in conflicts {
+            diags.add(Self::borrow_conflict_diag(loc, path, old.loc, &old.path));
+        }
+        diags
+    }
+
+    /// Builds the diagnostic for a borrow of `new_path` (at `new_loc`) conflicting with an
+    /// existing loan of `old_path` (at `old_loc`).
+    ///
+    /// `conflicting_loans` only ever returns loans whose path `overlaps` the new one, i.e. one is
+    /// always a (non-strict) prefix of the other -- there is no "fork partway through the
+    /// projection" case to report here, so the error always points directly at whichever path is
+    /// the fuller of the two (the shorter one names the same place, just less specifically).
+    fn borrow_conflict_diag(
+        new_loc: Loc,
+        new_path: &LoanPath,
+        old_loc: Loc,
+        old_path: &LoanPath,
+    ) -> crate::diagnostics::Diagnostic {
+        debug_assert!(new_path.is_prefix_of(old_path) || old_path.is_prefix_of(new_path));
+        let named = if new_path.fields.len() >= old_path.fields.len() {
+            new_path
+        } else {
+            old_path
+        };
+        diag!(
+            BorrowSafety::MutBorrow,
+            (
+                new_loc,
+                format!("Cannot borrow `{}` mutably more than once", named)
+            ),
+            (old_loc, "It is still being borrowed here"),
+        )
+    }
+
+    fn check_local_available(&self, loc: Loc, var: &Var) -> Diagnostics {
+        let mut diags = Diagnostics::new();
+        // Don't pile on more errors about locals whose state we can no longer trust once an
+        // earlier pass has already failed.
+        let available = matches!(self.locals.get(var), Some(LocalState::Available(_)));
+        if !self.has_errors && !available {
+            diags.add(diag!(
+                BorrowSafety::Unused,
+                (loc, format!("Use of unassigned local `{}`", var)),
+            ));
+        }
+        diags
+    }
+
+    pub fn move_local(&mut self, loc: Loc, var: &Var, _last_usage: bool) -> (Diagnostics, Value) {
+        let diags = self.check_local_available(loc, var);
+        let value = match self.locals.get(var) {
+            Some(LocalState::Available(value)) => value.clone(),
+            _ => Value::NonRef,
+        };
+        self.locals.remove(var);
+        self.locals.add(*var, LocalState::Unavailable).unwrap();
+        (diags, value)
+    }
+
+    pub fn copy_local(&mut self, loc: Loc, var: &Var) -> (Diagnostics, Value) {
+        let diags = self.check_local_available(loc, var);
+        let value = match self.locals.get(var) {
+            Some(LocalState::Available(value)) => value.clone(),
+            _ => Value::NonRef,
+        };
+        (diags, value)
+    }
+
+    /// Binds `value` to `var`, releasing whatever the local *previously* held (if anything) --
+    /// not the new value, which is now owned by this local and stays live until it is next
+    /// moved, copied out and released, or overwritten in turn.
+    pub fn assign_local(&mut self, _loc: Loc, var: &Var, value: Value) -> Diagnostics {
+        let previous = match self.locals.get(var) {
+            Some(LocalState::Available(old)) => Some(old.clone()),
+            _ => None,
+        };
+        if let Some(old) = previous {
+            self.release_value(old);
+        }
+        self.locals.remove(var);
+        self.locals.add(*var, LocalState::Available(value)).unwrap();
+        Diagnostics::new()
+    }
+
+    /// `reserve` requests a two-phase (reserved) mutable borrow instead of an immediately
+    /// active/exclusive one; see `conflicting_loans` for what that changes. It is ignored for
+    /// shared (non-mutable) borrows.
+    pub fn borrow_local(&mut self, loc: Loc, mut_: bool, var: &Var, reserve: bool) -> (Diagnostics, Value) {
+        let path = LoanPath::local(*var);
+        let conflicts = self.conflicting_loans(mut_, &path, &[]);
+        let diags = self.borrow_conflict_diags(loc, &path, conflicts);
+        let id = self.fresh_id();
+        let reserved = mut_ && reserve;
+        self.loans.insert(id, Loan { mut_, path, loc, reserved });
+        (diags, Value::Ref(id))
+    }
+
+    /// Borrows a (possibly multi-level) field projection off of `base` in one step, e.g. `&mut
+    /// s.a.x` extends `base`'s path by `a` then `x` before checking for conflicts just once,
+    /// against the final `s.a.x` path. Callers must collapse a chain of same-mutability nested
+    /// field borrows into a single call (see `borrow_chain` in `mod.rs`) rather than calling this
+    /// once per field: checking at each intermediate projection as well would report the same
+    /// conflict twice -- once against the transient intermediate path, once against the final one.
+    pub fn borrow_field_path(
+        &mut self,
+        loc: Loc,
+        mut_: bool,
+        base: Value,
+        fields: &[Field],
+        reserve: bool,
+    ) -> (Diagnostics, Value) {
+        let base_id = match base {
+            Value::Ref(id) => id,
+            Value::NonRef => panic!("ICE borrow_field of a non-reference value"),
+        };
+        let base_path = self.loans[&base_id].path.clone();
+        let path = fields.iter().fold(base_path, |p, f| p.extended(f));
+        let exclude = self.ancestors(base_id);
+        let conflicts = self.conflicting_loans(mut_, &path, &exclude);
+        let diags = self.borrow_conflict_diags(loc, &path, conflicts);
+        let id = self.fresh_id();
+        let reserved = mut_ && reserve;
+        self.loans.insert(id, Loan { mut_, path, loc, reserved });
+        self.parents.insert(id, base_id);
+        (diags, Value::Ref(id))
+    }
+
+    pub fn borrow_global(&mut self, loc: Loc, mut_: bool, struct_name: &StructName) -> (Diagnostics, Value) {
+        let mut diags = Diagnostics::new();
+        if !self.acquired_resources.contains_key(struct_name) {
+            diags.add(diag!(
+                BorrowSafety::GlobalAccess,
+                (
+                    loc,
+                    format!(
+                        "Invalid acquisition of `{}`: the function does not `acquire` it",
+                        struct_name
+                    )
+                ),
+            ));
+        }
+        let path = LoanPath::global(struct_name.clone());
+        let conflicts = self.conflicting_loans(mut_, &path, &[]);
+        diags.extend(self.borrow_conflict_diags(loc, &path, conflicts));
+        let id = self.fresh_id();
+        self.loans.insert(id, Loan { mut_, path, loc, reserved: false });
+        (diags, Value::Ref(id))
+    }
+
+    pub fn move_from(&mut self, loc: Loc, struct_name: &StructName) -> (Diagnostics, Value) {
+        let path = LoanPath::global(struct_name.clone());
+        let conflicts = self.conflicting_loans(true, &path, &[]);
+        let diags = self.borrow_conflict_diags(loc, &path, conflicts);
+        (diags, Value::NonRef)
+    }
+
+    pub fn freeze(&mut self, _loc: Loc, value: Value) -> (Diagnostics, Value) {
+        if let Value::Ref(id) = value {
+            if let Some(loan) = self.loans.get_mut(&id) {
+                loan.mut_ = false;
+            }
+        }
+        (Diagnostics::new(), value)
+    }
+
+    pub fn dereference(&mut self, _loc: Loc, _value: Value) -> (Diagnostics, Value) {
+        (Diagnostics::new(), Value::NonRef)
+    }
+
+    pub fn mutate(&mut self, loc: Loc, lvalue: Value) -> Diagnostics {
+        let id = match lvalue {
+            Value::Ref(id) => id,
+            Value::NonRef => panic!("ICE mutate of a non-reference value"),
+        };
+        let path = self.loans[&id].path.clone();
+        let exclude = self.ancestors(id);
+        let conflicts = self.conflicting_loans(true, &path, &exclude);
+        self.borrow_conflict_diags(loc, &path, conflicts)
+    }
+
+    pub fn call(
+        &mut self,
+        loc: Loc,
+        args: Values,
+        acquires: &BTreeMap<StructName, Loc>,
+        ret_ty: &Type,
+    ) -> (Diagnostics, Values) {
+        let mut diags = Diagnostics::new();
+        for (struct_name, acquired_loc) in acquires {
+            if !self.acquired_resources.contains_key(struct_name) {
+                diags.add(diag!(
+                    BorrowSafety::GlobalAccess,
+                    (
+                        loc,
+                        format!(
+                            "Invalid call: `{}` must be acquired by the calling function",
+                            struct_name
+                        )
+                    ),
+                    (*acquired_loc, "Acquired by the callee here"),
+                ));
+            }
+        }
+        // Activate each reserved borrow among `args` now that the call consumes it: it becomes a
+        // full exclusive borrow, so a shared loan of an overlapping path taken while it was
+        // reserved and still live here (e.g. `&v` passed as another argument of this call) is a
+        // conflict. Mutable borrows and mutations made in that window were already reported at
+        // their own site (see `conflicting_loans`), as was any loan older than the reservation.
+        for id in args.iter().filter_map(|v| match v { Value::Ref(id) => Some(*id), Value::NonRef => None }) {
+            let loan = match self.loans.get_mut(&id) {
+                Some(loan) if loan.reserved => loan,
+                _ => continue,
+            };
+            loan.reserved = false;
+            let (path, reserved_loc) = (loan.path.clone(), loan.loc);
+            let later_shared: Vec<Loan> = self.loans.range(RefID(id.0 + 1)..)
+                .filter(|(_, l)| !l.mut_ && l.path.overlaps(&path))
+                .map(|(_, l)| l.clone())
+                .collect();
+            diags.extend(self.borrow_conflict_diags(reserved_loc, &path, later_shared));
+        }
+        // The arguments are consumed by the call.
+        self.release_values(args);
+        (diags, self.fresh_return_values(loc, ret_ty))
+    }
+
+    fn fresh_return_values(&mut self, loc: Loc, ret_ty: &Type) -> Values {
+        let single = |s: &SingleType| -> Value {
+            match &s.value {
+                SingleType_::Base(_) => Value::NonRef,
+                SingleType_::Ref(mut_, _) => Value::Ref(self.fresh_unaliased_loan(loc, *mut_)),
+            }
+        };
+        match &ret_ty.value {
+            Type_::Unit => vec![],
+            Type_::Single(s) => vec![single(s)],
+            Type_::Multiple(ss) => ss.iter().map(single).collect(),
+        }
+    }
+
+    fn fresh_unaliased_loan(&mut self, loc: Loc, mut_: bool) -> RefID {
+        let id = self.fresh_id();
+        let path = LoanPath {
+            root: LoanRoot::Unaliased(id),
+            fields: vec![],
+        };
+        self.loans.insert(id, Loan { mut_, path, loc, reserved: false });
+        id
+    }
+
+    pub fn release_value(&mut self, value: Value) {
+        if let Value::Ref(id) = value {
+            self.loans.remove(&id);
+            self.parents.remove(&id);
+        }
+    }
+
+    pub fn release_values(&mut self, values: Values) {
+        values.into_iter().for_each(|v| self.release_value(v));
+    }
+
+    pub fn return_(&mut self, _loc: Loc, values: Values) -> Diagnostics {
+        self.release_values(values);
+        Diagnostics::new()
+    }
+
+    pub fn abort(&mut self) {
+        self.loans.clear();
+        self.parents.clear();
+    }
+
+    /// Renders this block's final borrow graph as a Graphviz DOT graph, for the
+    /// `debug_borrow_graph` dumps of the borrow checker: one node per local/global loan root,
+    /// one node per outstanding reference, and an edge from each reference to whatever it was
+    /// borrowed from, labeled with the last field of its path and colored by whether the
+    /// borrow is mutable. Purely for diagnosis; has
+    /// no effect on which programs are accepted.
+    pub fn borrow_graph_dot(&self, graph_name: &str, local_numbers: &UniqueMap<Var, usize>) -> String {
+        let mut root_ids: BTreeMap<LoanRoot, String> = BTreeMap::new();
+        for loan in self.loans.values() {
+            let next = root_ids.len();
+            root_ids
+                .entry(loan.path.root.clone())
+                .or_insert_with(|| format!("root{}", next));
+        }
+
+        let mut out = format!("digraph \"{}\" {{\n", graph_name);
+        for (root, node_id) in &root_ids {
+            let (label, shape) = match root {
+                LoanRoot::Local(v) => (
+                    format!("{} (local #{})", v, local_numbers.get(v).copied().unwrap_or(usize::MAX)),
+                    "box",
+                ),
+                LoanRoot::Global(s) => (format!("{}", s), "ellipse"),
+                LoanRoot::Unaliased(_) => ("_".to_string(), "point"),
+            };
+            out.push_str(&format!("  {} [label=\"{}\", shape={}];\n", node_id, label, shape));
+        }
+        for (id, loan) in &self.loans {
+            let node_id = format!("ref{}", id.0);
+            let fill = if loan.mut_ { "lightblue" } else { "lightgray" };
+            out.push_str(&format!(
+                "  {} [label=\"{}\", shape=oval, style=filled, fillcolor={}];\n",
+                node_id, loan.path, fill
+            ));
+            let parent_node = match self.parents.get(id) {
+                Some(parent_id) => format!("ref{}", parent_id.0),
+                None => root_ids[&loan.path.root].clone(),
+            };
+            let field_label = loan.path.fields.last().map(|f| f.to_string()).unwrap_or_default();
+            let edge_color = if loan.mut_ { "blue" } else { "gray" };
+            out.push_str(&format!(
+                "  {} -> {} [label=\"{}\", color={}];\n",
+                parent_node, node_id, field_label, edge_color
+            ));
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+impl AbstractDomain for BorrowState {
+    fn join(&mut self, other: &Self) -> JoinResult {
+        let before = self.clone();
+        for (v, state) in self.locals.key_cloned_iter_mut() {
+            let other_state = other.locals.get(v).cloned().unwrap_or(LocalState::Unavailable);
+            if *state != other_state {
+                *state = LocalState::Unavailable;
+            }
+        }
+        for (id, loan) in &other.loans {
+            self.loans.entry(*id).or_insert_with(|| loan.clone());
+        }
+        for (id, parent) in &other.parents {
+            self.parents.entry(*id).or_insert(*parent);
+        }
+        if before == *self {
+            JoinResult::Unchanged
+        } else {
+            JoinResult::Changed
+        }
+    }
+}
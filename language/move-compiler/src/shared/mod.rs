@@ -0,0 +1,59 @@
+// Copyright (c) The Diem Core Contributors
+// Copyright (c) The Move Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::diagnostics::Diagnostics;
+
+pub mod unique_map;
+
+/// Compiler-wide on/off switches, set once up front from the command line (or API callers
+/// constructing a `CompilationEnv` directly) and read by passes anywhere in the pipeline.
+#[derive(Clone, Debug, Default)]
+pub struct Flags {
+    /// Print each block's settled borrow graph to stderr as a Graphviz DOT dump while running
+    /// the borrow-safety pass; see `cfgir::borrows::BorrowState::borrow_graph_dot`.
+    debug_borrow_graph: bool,
+}
+
+impl Flags {
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    pub fn set_debug_borrow_graph(mut self, debug_borrow_graph: bool) -> Self {
+        self.debug_borrow_graph = debug_borrow_graph;
+        self
+    }
+
+    pub fn debug_borrow_graph(&self) -> bool {
+        self.debug_borrow_graph
+    }
+}
+
+/// Shared, mutable state threaded through every pass of the compiler: the flags it was invoked
+/// with, and the diagnostics accumulated so far.
+pub struct CompilationEnv {
+    flags: Flags,
+    diags: Diagnostics,
+}
+
+impl CompilationEnv {
+    pub fn new(flags: Flags) -> Self {
+        Self {
+            flags,
+            diags: Diagnostics::new(),
+        }
+    }
+
+    pub fn flags(&self) -> &Flags {
+        &self.flags
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.diags.is_empty()
+    }
+
+    pub fn add_diags(&mut self, diags: Diagnostics) {
+        self.diags.extend(diags);
+    }
+}
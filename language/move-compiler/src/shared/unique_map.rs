@@ -0,0 +1,49 @@
+// Copyright (c) The Diem Core Contributors
+// Copyright (c) The Move Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::BTreeMap;
+
+/// An ordered map that rejects re-insertion of an already-present key: `add` returns the
+/// rejected `(key, value)` back to the caller instead of silently overwriting, so that binding
+/// the same name twice is a decision the caller has to make explicitly (usually by first calling
+/// `remove`, as the borrow-safety pass does whenever a local's state changes).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UniqueMap<K: Ord, V>(BTreeMap<K, V>);
+
+impl<K: Ord + Clone, V> UniqueMap<K, V> {
+    pub fn new() -> Self {
+        Self(BTreeMap::new())
+    }
+
+    pub fn add(&mut self, k: K, v: V) -> Result<(), (K, V)> {
+        if self.0.contains_key(&k) {
+            Err((k, v))
+        } else {
+            self.0.insert(k, v);
+            Ok(())
+        }
+    }
+
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        self.0.remove(k)
+    }
+
+    pub fn get(&self, k: &K) -> Option<&V> {
+        self.0.get(k)
+    }
+
+    pub fn key_cloned_iter(&self) -> impl Iterator<Item = (K, &V)> {
+        self.0.iter().map(|(k, v)| (k.clone(), v))
+    }
+
+    pub fn key_cloned_iter_mut(&mut self) -> impl Iterator<Item = (K, &mut V)> {
+        self.0.iter_mut().map(|(k, v)| (k.clone(), v))
+    }
+}
+
+impl<K: Ord + Clone, V> Default for UniqueMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}